@@ -0,0 +1,178 @@
+// Copyright 2019 thibaultam (lathib2@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned, Shared};
+
+use crate::{Quantile, Stream};
+
+// Number of lock-free stacks observations are spread across, to keep contention on any
+// single one low without growing a stack per thread.
+const SHARD_COUNT: usize = 16;
+
+// A node of the per-shard, lock-free, append-only stack producers push into.
+struct Node<T> {
+    value: T,
+    next: Atomic<Node<T>>,
+}
+
+/// `ConcurrentStream` lets many producer threads call [`observe`](#method.observe) without
+/// contending on a global lock, and lets a reader take a consistent
+/// [`query`](#method.query) snapshot, the way metrics libraries combine atomic per-shard
+/// buffers with epoch-based reclamation instead of requiring callers to wrap every
+/// observation in a mutex as [`Stream`](struct.Stream.html) does.
+///
+/// Producers push into one of `SHARD_COUNT` lock-free stacks, guarded by `crossbeam-epoch` so
+/// writes never block on a reader. A snapshot atomically swaps each shard's stack for a fresh
+/// empty one, drains the retired nodes into a private `Stream`, runs its usual compression,
+/// and answers the query from there.
+pub struct ConcurrentStream<T: Copy + PartialOrd + Default + Send> {
+    shards: Vec<Atomic<Node<T>>>,
+    snapshot: Mutex<Stream<T>>,
+}
+
+impl<T: Copy + PartialOrd + Default + Send> ConcurrentStream<T> {
+    /// Creates a new concurrent stream that will track the quantiles passed as parameter.
+    pub fn new(targets: Vec<Quantile>) -> ConcurrentStream<T> {
+        ConcurrentStream {
+            shards: (0..SHARD_COUNT).map(|_| Atomic::null()).collect(),
+            snapshot: Mutex::new(Stream::new(targets)),
+        }
+    }
+
+    /// Records `value`. Safe to call concurrently from any number of producer threads: this
+    /// never blocks on the lock used by [`query`](#method.query).
+    pub fn observe(&self, value: T) {
+        let shard = &self.shards[self.shard_index()];
+        let guard = &epoch::pin();
+        let mut node = Owned::new(Node {
+            value,
+            next: Atomic::null(),
+        });
+        loop {
+            let head = shard.load(Ordering::Acquire, guard);
+            node.next.store(head, Ordering::Relaxed);
+            match shard.compare_exchange(head, node, Ordering::Release, Ordering::Relaxed, guard) {
+                Ok(_) => break,
+                Err(e) => node = e.new,
+            }
+        }
+    }
+
+    /// Takes a consistent snapshot of every observation recorded so far and returns the value
+    /// within the defined error margin around `quantile`.
+    pub fn query(&self, quantile: f64) -> T {
+        let guard = &epoch::pin();
+        let mut stream = self.snapshot.lock().unwrap();
+        for shard in self.shards.iter() {
+            let retired = shard.swap(Shared::null(), Ordering::AcqRel, guard);
+            self.drain(retired, &mut stream, guard);
+        }
+        stream.query(quantile)
+    }
+
+    // Walks a retired shard's stack, feeding every value into `stream` and reclaiming each
+    // node once the current epoch guarantees no reader can still be looking at it.
+    fn drain<'g>(
+        &self,
+        mut current: Shared<'g, Node<T>>,
+        stream: &mut Stream<T>,
+        guard: &'g epoch::Guard,
+    ) {
+        while !current.is_null() {
+            // Safety: `current` was unlinked from its shard by the swap in `query` above, so
+            // this is the only thread draining it.
+            let node = unsafe { current.deref() };
+            stream.observe(node.value);
+            let next = node.next.load(Ordering::Acquire, guard);
+            unsafe { guard.defer_destroy(current) };
+            current = next;
+        }
+    }
+
+    // Picks a shard for the calling thread.
+    fn shard_index(&self) -> usize {
+        let mut hasher = DefaultHasher::new();
+        std::thread::current().id().hash(&mut hasher);
+        (hasher.finish() as usize) % SHARD_COUNT
+    }
+}
+
+impl<T: Copy + PartialOrd + Default + Send> Drop for ConcurrentStream<T> {
+    // Nodes pushed by `observe` are otherwise only reclaimed inside `query`'s drain, so any
+    // observation made after the last `query` (or if `query` is never called at all) would
+    // leak when the stream is dropped.
+    fn drop(&mut self) {
+        let guard = &epoch::pin();
+        for shard in self.shards.iter() {
+            let mut current = shard.swap(Shared::null(), Ordering::AcqRel, guard);
+            while !current.is_null() {
+                // Safety: we have `&mut self` here, so no producer or reader can still be
+                // holding a reference into this shard's stack.
+                let owned = unsafe { current.into_owned() };
+                current = owned.next.load(Ordering::Relaxed, guard);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn observe_from_multiple_threads_then_query() {
+        let stream = Arc::new(ConcurrentStream::new(vec![Quantile::new(0.5, 0.05)]));
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let stream = Arc::clone(&stream);
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        stream.observe((t * 25 + i + 1) as f64);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let median = stream.query(0.5);
+        assert!(
+            (45.0..=55.0).contains(&median),
+            "expected a value close to the true median of 50.0, got {}",
+            median
+        );
+    }
+
+    #[test]
+    fn drop_reclaims_observations_made_after_the_last_query() {
+        // Regression test: `observe` pushes nodes onto the shard stacks that used to only be
+        // reclaimed inside `query`'s drain, so a stream dropped without ever calling `query`
+        // would leak every node. Run under a leak checker (e.g. valgrind or miri) to confirm
+        // nothing survives the drop below.
+        let stream = ConcurrentStream::<f64>::new(vec![Quantile::new(0.5, 0.05)]);
+        for i in 0..100 {
+            stream.observe(i as f64);
+        }
+        drop(stream);
+    }
+}
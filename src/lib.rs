@@ -40,18 +40,39 @@ assert_eq!(50.0_f64, stream.query(0.5));
 assert_eq!(90.0_f64, stream.query(0.9));
 ```
 
+`Stream` is generic over the type of the observed values, so it can track
+quantiles of anything orderable (`u64`, `Duration`, ...) without forcing a
+lossy cast to `f64`.
+
+`Stream` requires its targets to be declared up front. If you need to query an arbitrary
+quantile at runtime instead, see [`UniformStream`](struct.UniformStream.html), which trades
+the biased mode's extra precision at predeclared targets for a single epsilon accuracy
+guarantee across the whole distribution.
+
 # Thread safety
 
-This implementation is NOT thread safe by design. To make it thread safe you must lock
-before using the public methods of the [`Stream`](struct.Stream.html).
+[`Stream`](struct.Stream.html) is NOT thread safe by design. To make it thread safe you must
+lock before using its public methods. [`ConcurrentStream`](struct.ConcurrentStream.html) is an
+alternative that can be observed from many threads without a lock.
 !*/
 
 use std::cell::RefCell;
 use std::fmt;
 
+mod concurrent;
+mod serialize;
+mod uniform;
+mod windowed;
+
+pub use concurrent::ConcurrentStream;
+pub use serialize::{DeserializeError, SerializableValue};
+pub use uniform::UniformStream;
+pub use windowed::WindowedStream;
+
 /// The quantile that needs to be computed and its associated error margin,
 /// such that the value returned is be at a rank ±epsilon
 /// percent around the exact value.
+#[derive(Clone)]
 pub struct Quantile {
     value: f64,
     error: f64,
@@ -90,22 +111,34 @@ impl fmt::Debug for Quantile {
     }
 }
 
+// u and v are derived from value/error, so comparing them would be redundant.
+impl PartialEq for Quantile {
+    fn eq(&self, other: &Quantile) -> bool {
+        self.value == other.value && self.error == other.error
+    }
+}
+
 const BUFFER_SIZE: usize = 500;
 
-/// `Stream` computes the requested quantiles for a stream of `f64`.
-pub struct Stream {
+/// `Stream` computes the requested quantiles for a stream of values of type `T`.
+///
+/// `T` only needs to be comparable, copyable and to have a `Default` (returned by
+/// [`query`](#method.query) on an empty stream): the rank arithmetic used to
+/// bound the error (`g`, `d`, the invariant function) is always carried out
+/// in `f64`/`i64`, regardless of the type of the observed values.
+pub struct Stream<T: Copy + PartialOrd + Default> {
     targets: Vec<Quantile>,
-    samples: Vec<Sample>,
-    new_samples: RefCell<Vec<Sample>>,
+    samples: Vec<Sample<T>>,
+    new_samples: RefCell<Vec<Sample<T>>>,
     number_items: u64, // Number of items seen in the stream
-    buffer: Vec<f64>,  // Buffer to temporarily store the oberserved values
+    buffer: Vec<T>,    // Buffer to temporarily store the oberserved values
 }
 
 // A sample measurement with error for compression.
 #[derive(Debug, Clone)]
-struct Sample {
+struct Sample<T: Copy + PartialOrd> {
     // v(i) in the paper, the value of this sample.
-    v: f64,
+    v: T,
 
     // g(i) in the paper, the difference between the lowest rank of this sample and its predecessor.
     g: f64,
@@ -114,9 +147,9 @@ struct Sample {
     d: i64,
 }
 
-impl Stream {
+impl<T: Copy + PartialOrd + Default> Stream<T> {
     /// Creates a new stream that will track the quantiles passed as parameter.
-    pub fn new(quantiles: Vec<Quantile>) -> Stream {
+    pub fn new(quantiles: Vec<Quantile>) -> Stream<T> {
         Stream {
             targets: quantiles,
             samples: Vec::new(),
@@ -142,7 +175,7 @@ impl Stream {
     }
 
     /// Adds a new value to the stream.
-    pub fn observe(&mut self, value: f64) {
+    pub fn observe(&mut self, value: T) {
         self.buffer.push(value);
         if self.buffer.len() == BUFFER_SIZE {
             self.flush_and_compress();
@@ -177,20 +210,19 @@ impl Stream {
             }
 
             // Insert new value
-            let new_sample: Sample;
-            if idx == 0 || idx == self.samples.len() {
-                new_sample = Sample {
+            let new_sample = if idx == 0 || idx == self.samples.len() {
+                Sample {
                     v: value,
                     g: 1.0,
                     d: 0,
-                };
+                }
             } else {
-                new_sample = Sample {
+                Sample {
                     v: value,
                     g: 1.0_f64,
                     d: self.invariant(prev_r + self.samples[idx].g).floor() as i64 - 1,
-                };
-            }
+                }
+            };
             self.merge_and_insert(&new_sample, &mut prev_r);
             self.number_items += 1;
         }
@@ -211,7 +243,7 @@ impl Stream {
     // It always inserts the `current` sample, either by adding it to the list or by merging it.
     // In addition it will maintain the value of `prev_r` by adding the appropriate value to it
     // when a merge occurs.
-    fn merge_and_insert(&self, current: &Sample, prev_r: &mut f64) {
+    fn merge_and_insert(&self, current: &Sample<T>, prev_r: &mut f64) {
         let mut new_samples = self.new_samples.borrow_mut();
         if new_samples.is_empty() {
             new_samples.push(current.clone());
@@ -232,14 +264,14 @@ impl Stream {
     }
 
     /// Retrieve the value that is within the defined error margin around `quantile`.
-    /// This will default to `0.0` as a convention if no value have been fed to this strean
-    /// using [`observe()`](struct.Stream.html#method.observe).
+    /// This will default to `T::default()` as a convention if no value have been fed to this
+    /// strean using [`observe()`](struct.Stream.html#method.observe).
     ///
     /// # panic
     ///
     /// This will panic in debug mode only if the requested `target` is not a target defined when
     /// constructed the stream with [`new`](struct.Stream.html#method.new).
-    pub fn query(&mut self, quantile: f64) -> f64 {
+    pub fn query(&mut self, quantile: f64) -> T {
         debug_assert!(self.targets.iter().filter(|t| t.value == quantile).count() == 1,
             "The queried quantile {} should have been defined when constructing the stream (got: {:?})",
             quantile, &self.targets);
@@ -247,8 +279,7 @@ impl Stream {
         self.flush_and_compress();
 
         if self.samples.is_empty() {
-            println!("Empty");
-            return 0.0;
+            return T::default();
         }
 
         let t = quantile * self.number_items as f64
@@ -264,6 +295,93 @@ impl Stream {
         let last = &self.samples[self.samples.len() - 1];
         last.v
     }
+
+    /// Merges `other` into this stream, so that it reports quantiles over the union of the
+    /// two streams' observations. This is the main reason to use this algorithm in a
+    /// map-reduce setting: partial summaries computed independently on different
+    /// shards/threads can be combined into a single one without re-observing the raw data.
+    ///
+    /// Both streams must have been created with the same `targets`, in the same order.
+    ///
+    /// # panic
+    ///
+    /// Panics if `self` and `other` don't track the same quantiles.
+    pub fn merge(&mut self, other: &mut Stream<T>) {
+        assert_eq!(
+            self.targets, other.targets,
+            "streams can only be merged if they track the same quantiles"
+        );
+
+        self.flush_and_compress();
+        other.flush_and_compress();
+
+        let merged = merge_samples(&self.samples, &other.samples);
+        self.number_items += other.number_items;
+
+        // Run the merged samples back through the usual compression machinery, now that
+        // `number_items` reflects the combined stream, to restore the size bound.
+        let mut prev_r = 0.0_f64;
+        for sample in merged.iter() {
+            self.merge_and_insert(sample, &mut prev_r);
+        }
+        std::mem::swap(&mut self.samples, &mut self.new_samples.borrow_mut());
+        self.new_samples.borrow_mut().clear();
+    }
+
+    /// Same as [`merge`](struct.Stream.html#method.merge) but takes and returns ownership,
+    /// which is convenient when combining streams in a fold/reduce.
+    pub fn merge_owned(mut self, mut other: Stream<T>) -> Stream<T> {
+        self.merge(&mut other);
+        self
+    }
+}
+
+// Which of the two streams being merged a sample originated from.
+#[derive(Clone, Copy, PartialEq)]
+enum Origin {
+    A,
+    B,
+}
+
+// Sort-merges the samples of two compressed streams by `v`, then adjusts the error bound
+// `d` of each sample to account for the uncertainty of the *other* stream: a sample now sits
+// between neighbors it never saw, so it must absorb their local uncertainty. It is bumped by
+// `(g + d)` of the nearest following sample that came from the other stream, minus 1 - or left
+// untouched at the extreme ends, where no such neighbor exists.
+fn merge_samples<T: Copy + PartialOrd>(a: &[Sample<T>], b: &[Sample<T>]) -> Vec<Sample<T>> {
+    let mut merged: Vec<(Sample<T>, Origin)> = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].v <= b[j].v {
+            merged.push((a[i].clone(), Origin::A));
+            i += 1;
+        } else {
+            merged.push((b[j].clone(), Origin::B));
+            j += 1;
+        }
+    }
+    while i < a.len() {
+        merged.push((a[i].clone(), Origin::A));
+        i += 1;
+    }
+    while j < b.len() {
+        merged.push((b[j].clone(), Origin::B));
+        j += 1;
+    }
+
+    let len = merged.len();
+    for idx in 0..len {
+        let origin = merged[idx].1;
+        let bump = merged[(idx + 1)..len]
+            .iter()
+            .find(|(_, o)| *o != origin)
+            .map(|(s, _)| s.g + s.d as f64 - 1.0);
+        if let Some(bump) = bump {
+            merged[idx].0.d += bump as i64;
+        }
+    }
+
+    merged.into_iter().map(|(s, _)| s).collect()
 }
 
 #[cfg(test)]
@@ -357,32 +475,72 @@ mod tests {
     #[test]
     #[should_panic]
     fn panic_if_quantile_is_negative() {
-        let _stream = Stream::new(vec![Quantile::new(-0.5, 0.05)]);
+        let _stream: Stream<f64> = Stream::new(vec![Quantile::new(-0.5, 0.05)]);
     }
 
     #[test]
     #[should_panic]
     fn panic_if_quantile_is_greater_than_one() {
-        let _stream = Stream::new(vec![Quantile::new(1.5, 0.05)]);
+        let _stream: Stream<f64> = Stream::new(vec![Quantile::new(1.5, 0.05)]);
     }
 
     #[test]
     #[should_panic]
     fn panic_if_error_is_negative() {
-        let _stream = Stream::new(vec![Quantile::new(0.5, -0.05)]);
+        let _stream: Stream<f64> = Stream::new(vec![Quantile::new(0.5, -0.05)]);
     }
 
     #[test]
     #[should_panic]
     fn panic_if_error_is_greater_than_one() {
-        let _stream = Stream::new(vec![Quantile::new(0.5, 1.5)]);
+        let _stream: Stream<f64> = Stream::new(vec![Quantile::new(0.5, 1.5)]);
     }
 
     #[test]
     #[should_panic]
     fn panic_if_query_an_untrack_quantile() {
-        let mut stream = Stream::new(vec![Quantile::new(0.9, 0.01)]);
+        let mut stream: Stream<f64> = Stream::new(vec![Quantile::new(0.9, 0.01)]);
         stream.query(0.5);
     }
 
+    #[test]
+    fn stream_can_be_used_with_an_integer_type() {
+        let mut stream: Stream<u64> = Stream::new(vec![Quantile::new(0.5, 0.05)]);
+
+        for i in 1..=10u64 {
+            stream.observe(i);
+        }
+
+        assert_eq!(5, stream.query(0.5));
+    }
+
+    #[test]
+    fn merge_combines_two_streams() {
+        let mut a = Stream::new(vec![Quantile::new(0.5, 0.05)]);
+        let mut b = Stream::new(vec![Quantile::new(0.5, 0.05)]);
+
+        for i in 1..51 {
+            a.observe(i as f64);
+        }
+        for i in 51..101 {
+            b.observe(i as f64);
+        }
+
+        a.merge(&mut b);
+        let median = a.query(0.5);
+        assert!(
+            (45.0..=55.0).contains(&median),
+            "expected a value close to the true median of 50.0, got {}",
+            median
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn merge_panics_if_targets_differ() {
+        let mut a: Stream<f64> = Stream::new(vec![Quantile::new(0.5, 0.05)]);
+        let mut b: Stream<f64> = Stream::new(vec![Quantile::new(0.9, 0.05)]);
+        a.merge(&mut b);
+    }
+
 }
@@ -0,0 +1,300 @@
+// Copyright 2019 thibaultam (lathib2@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::{Quantile, Sample, Stream, BUFFER_SIZE};
+
+/// Errors that can occur when reconstructing a [`Stream`](struct.Stream.html) from bytes
+/// produced by [`Stream::serialize`](struct.Stream.html#method.serialize).
+#[derive(Debug)]
+pub enum DeserializeError {
+    /// The byte slice ended before all the expected fields could be read.
+    UnexpectedEof,
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeserializeError::UnexpectedEof => write!(f, "unexpected end of input"),
+        }
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64, DeserializeError> {
+    let mut result = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*pos).ok_or(DeserializeError::UnexpectedEof)?;
+        *pos += 1;
+        result |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+}
+
+// Maps signed integers to unsigned ones so that small negative values, as common in `∆`,
+// still varint-encode to few bytes.
+fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn write_f64(buf: &mut Vec<u8>, value: f64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn read_f64(bytes: &[u8], pos: &mut usize) -> Result<f64, DeserializeError> {
+    let end = *pos + 8;
+    let slice = bytes.get(*pos..end).ok_or(DeserializeError::UnexpectedEof)?;
+    *pos = end;
+    let mut le_bytes = [0u8; 8];
+    le_bytes.copy_from_slice(slice);
+    Ok(f64::from_le_bytes(le_bytes))
+}
+
+/// A lossless, order-preserving conversion between `T` and a `u64`, so
+/// [`Stream::serialize`](struct.Stream.html#method.serialize) can delta+varint-encode the value
+/// column the same way it already does `g`/`∆`, for any `T` `Stream` can be instantiated with
+/// (`f64`, `u64`, `i64`, `Duration`, ...), not just types that happen to round-trip through
+/// `f64`.
+///
+/// `to_ordered_bits` must preserve `T`'s `PartialOrd` order: since `Stream` always keeps its
+/// samples sorted by value, this guarantees the bits are non-decreasing from one sample to the
+/// next, so the column compresses as well as `g`/`∆` do.
+pub trait SerializableValue: Copy {
+    /// Converts `self` to its order-preserving `u64` representation.
+    fn to_ordered_bits(self) -> u64;
+
+    /// Reconstructs the value previously returned by `to_ordered_bits`. Never called with any
+    /// other input.
+    fn from_ordered_bits(bits: u64) -> Self;
+}
+
+impl SerializableValue for f64 {
+    fn to_ordered_bits(self) -> u64 {
+        let bits = self.to_bits();
+        if bits & (1 << 63) != 0 {
+            !bits
+        } else {
+            bits | (1 << 63)
+        }
+    }
+
+    fn from_ordered_bits(bits: u64) -> Self {
+        let bits = if bits & (1 << 63) != 0 {
+            bits & !(1 << 63)
+        } else {
+            !bits
+        };
+        f64::from_bits(bits)
+    }
+}
+
+impl SerializableValue for u64 {
+    fn to_ordered_bits(self) -> u64 {
+        self
+    }
+
+    fn from_ordered_bits(bits: u64) -> Self {
+        bits
+    }
+}
+
+impl SerializableValue for i64 {
+    fn to_ordered_bits(self) -> u64 {
+        (self as u64) ^ (1 << 63)
+    }
+
+    fn from_ordered_bits(bits: u64) -> Self {
+        (bits ^ (1 << 63)) as i64
+    }
+}
+
+// Exact and order-preserving only for durations below `u64::MAX` nanoseconds (about 584
+// years): the nanosecond count of a longer duration is truncated to its low 64 bits.
+impl SerializableValue for std::time::Duration {
+    fn to_ordered_bits(self) -> u64 {
+        self.as_nanos() as u64
+    }
+
+    fn from_ordered_bits(bits: u64) -> Self {
+        std::time::Duration::from_nanos(bits)
+    }
+}
+
+impl<T> Stream<T>
+where
+    T: Copy + PartialOrd + Default + SerializableValue,
+{
+    /// Serializes this stream into a compact column-oriented binary representation, so a
+    /// summary can be persisted or shipped over the wire and later reconstructed with
+    /// [`deserialize`](#method.deserialize) (and possibly merged with
+    /// [`merge`](#method.merge) on another node).
+    ///
+    /// This flushes any observations still buffered by [`observe`](#method.observe) first, the
+    /// same way [`query`](#method.query) and [`merge`](#method.merge) do, so the output always
+    /// reflects every value observed so far.
+    ///
+    /// The `g` column is encoded as variable-byte (LEB128) integers and the `∆` column as
+    /// zigzag + variable-byte integers, which compresses very well since most samples have
+    /// `g = 1` and a small `∆`. Sample values are
+    /// encoded through [`SerializableValue`] and, since samples are always kept sorted, stored
+    /// as a variable-byte delta from the previous sample's bits rather than raw 8-byte values.
+    pub fn serialize(&mut self) -> Vec<u8> {
+        self.flush_and_compress();
+
+        let mut buf = Vec::new();
+
+        write_varint(&mut buf, self.number_items);
+
+        write_varint(&mut buf, self.targets.len() as u64);
+        for target in self.targets.iter() {
+            write_f64(&mut buf, target.value);
+            write_f64(&mut buf, target.error);
+        }
+
+        write_varint(&mut buf, self.samples.len() as u64);
+        let mut prev_bits = 0_u64;
+        for sample in self.samples.iter() {
+            write_varint(&mut buf, sample.g as u64);
+            write_varint(&mut buf, zigzag_encode(sample.d));
+            let bits = sample.v.to_ordered_bits();
+            write_varint(&mut buf, bits.wrapping_sub(prev_bits));
+            prev_bits = bits;
+        }
+
+        buf
+    }
+
+    /// Reconstructs a stream previously serialized with [`serialize`](#method.serialize).
+    pub fn deserialize(bytes: &[u8]) -> Result<Stream<T>, DeserializeError> {
+        let mut pos = 0;
+
+        let number_items = read_varint(bytes, &mut pos)?;
+
+        let target_count = read_varint(bytes, &mut pos)?;
+        let mut targets = Vec::with_capacity(target_count as usize);
+        for _ in 0..target_count {
+            let value = read_f64(bytes, &mut pos)?;
+            let error = read_f64(bytes, &mut pos)?;
+            targets.push(Quantile::new(value, error));
+        }
+
+        let sample_count = read_varint(bytes, &mut pos)?;
+        let mut samples = Vec::with_capacity(sample_count as usize);
+        let mut prev_bits = 0_u64;
+        for _ in 0..sample_count {
+            let g = read_varint(bytes, &mut pos)? as f64;
+            let d = zigzag_decode(read_varint(bytes, &mut pos)?);
+            let bits = prev_bits.wrapping_add(read_varint(bytes, &mut pos)?);
+            prev_bits = bits;
+            samples.push(Sample { v: T::from_ordered_bits(bits), g, d });
+        }
+
+        Ok(Stream {
+            targets,
+            samples,
+            new_samples: RefCell::new(Vec::new()),
+            number_items,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_then_deserialize_round_trips() {
+        let mut stream = Stream::new(vec![Quantile::new(0.5, 0.05), Quantile::new(0.9, 0.05)]);
+        for i in 1..101 {
+            stream.observe(i as f64);
+        }
+
+        let bytes = stream.serialize();
+        let mut restored: Stream<f64> = Stream::deserialize(&bytes).unwrap();
+
+        assert_eq!(stream.query(0.5), restored.query(0.5));
+        assert_eq!(stream.query(0.9), restored.query(0.9));
+    }
+
+    #[test]
+    fn serialize_flushes_observations_still_in_the_buffer() {
+        // Regression test: `serialize` used to take `&self` and could not flush, so anything
+        // still sitting in `buffer` (i.e. anything observed since the last `query`/`merge`) was
+        // silently missing from the output.
+        let mut stream: Stream<f64> = Stream::new(vec![Quantile::new(0.5, 0.05)]);
+        stream.observe(1.0);
+        stream.observe(2.0);
+        stream.observe(3.0);
+
+        let bytes = stream.serialize();
+        let mut restored: Stream<f64> = Stream::deserialize(&bytes).unwrap();
+
+        assert_eq!(stream.query(0.5), restored.query(0.5));
+    }
+
+    #[test]
+    fn deserialize_rejects_truncated_input() {
+        let mut stream: Stream<f64> = Stream::new(vec![Quantile::new(0.5, 0.05)]);
+        let bytes = stream.serialize();
+
+        assert!(Stream::<f64>::deserialize(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn serialize_then_deserialize_round_trips_for_integer_types() {
+        let mut stream: Stream<u64> = Stream::new(vec![Quantile::new(0.5, 0.05)]);
+        for i in 1..101u64 {
+            stream.observe(i);
+        }
+        stream.query(0.5);
+
+        let bytes = stream.serialize();
+        let mut restored: Stream<u64> = Stream::deserialize(&bytes).unwrap();
+
+        assert_eq!(stream.query(0.5), restored.query(0.5));
+    }
+
+    #[test]
+    fn ordered_bits_round_trip_exactly() {
+        for value in [f64::MIN, -1.5, 0.0, 1.5, f64::MAX] {
+            assert_eq!(value.to_bits(), f64::from_ordered_bits(value.to_ordered_bits()).to_bits());
+        }
+        for value in [i64::MIN, -1, 0, 1, i64::MAX] {
+            assert_eq!(value, i64::from_ordered_bits(value.to_ordered_bits()));
+        }
+    }
+}
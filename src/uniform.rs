@@ -0,0 +1,220 @@
+// Copyright 2019 thibaultam (lathib2@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::RefCell;
+
+use crate::BUFFER_SIZE;
+
+// An (v, g, delta) tuple as defined by the Greenwald-Khanna algorithm. Structurally the same
+// shape as `Sample` in the biased mode, but compressed against a single global `epsilon`
+// rather than a per-target invariant.
+#[derive(Debug, Clone)]
+struct Entry<T: Copy + PartialOrd> {
+    v: T,
+    g: f64,
+    delta: i64,
+}
+
+/// `UniformStream` computes an approximate quantile summary with a single epsilon-accuracy
+/// guarantee across the *whole* distribution, using the Greenwald-Khanna algorithm, rather
+/// than a set of predeclared biased targets like [`Stream`](struct.Stream.html) does.
+///
+/// This trades a bit of the biased mode's precision at the extreme quantiles for the ability
+/// to [`query`](#method.query) *any* quantile at runtime, instead of only the ones passed to
+/// `new`.
+pub struct UniformStream<T: Copy + PartialOrd + Default> {
+    epsilon: f64,
+    samples: Vec<Entry<T>>,
+    new_samples: RefCell<Vec<Entry<T>>>,
+    number_items: u64,
+    buffer: Vec<T>,
+}
+
+impl<T: Copy + PartialOrd + Default> UniformStream<T> {
+    /// Creates a new uniform stream guaranteeing that any quantile queried later will be
+    /// within `epsilon` of its exact rank.
+    ///
+    /// # panic
+    ///
+    /// Panics if `epsilon <= 0` or `epsilon > 1`.
+    pub fn new(epsilon: f64) -> UniformStream<T> {
+        assert!(epsilon > 0.0, "epsilon should be > 0: {}", epsilon);
+        assert!(epsilon <= 1.0, "epsilon should be <= 1: {}", epsilon);
+
+        UniformStream {
+            epsilon,
+            samples: Vec::new(),
+            new_samples: RefCell::new(Vec::new()),
+            number_items: 0,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+        }
+    }
+
+    // The compression bound `floor(2 * epsilon * n)` shared by every sample, as opposed to
+    // the per-target invariant used in the biased mode.
+    fn invariant(&self) -> f64 {
+        (2.0 * self.epsilon * self.number_items as f64).floor()
+    }
+
+    /// Adds a new value to the stream.
+    pub fn observe(&mut self, value: T) {
+        self.buffer.push(value);
+        if self.buffer.len() == BUFFER_SIZE {
+            self.flush_and_compress();
+        }
+    }
+
+    // Same shape as `Stream::flush_and_compress`, but the insertion delta and the merge
+    // threshold both come from the single global `invariant()` instead of the biased f(r).
+    fn flush_and_compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        self.buffer.sort_by(|x, y| x.partial_cmp(y).unwrap());
+
+        let mut idx = 0;
+        let mut prev_r = 0.0_f64;
+        // `self.samples` only holds samples left over from *previous* flushes, so on the very
+        // first flush it is empty and `idx == 0 || idx == self.samples.len()` would hold for
+        // every value in this batch, not just the true extremes of the stream as a whole.
+        // Track the batch's own bounds instead, so `delta` is only ever 0 for samples that
+        // really sit at an edge of everything observed so far.
+        let max_buffered_value = *self.buffer.last().unwrap();
+
+        for value in self.buffer.iter() {
+            let value = *value;
+            while idx < self.samples.len() && self.samples[idx].v <= value {
+                self.merge_and_insert(&self.samples[idx], &mut prev_r);
+                idx += 1;
+            }
+
+            let is_lower_extreme = self.new_samples.borrow().is_empty();
+            let is_upper_extreme = idx == self.samples.len() && value == max_buffered_value;
+
+            let new_entry = if is_lower_extreme || is_upper_extreme {
+                Entry {
+                    v: value,
+                    g: 1.0,
+                    delta: 0,
+                }
+            } else {
+                Entry {
+                    v: value,
+                    g: 1.0_f64,
+                    delta: self.invariant() as i64 - 1,
+                }
+            };
+            self.merge_and_insert(&new_entry, &mut prev_r);
+            self.number_items += 1;
+        }
+        while idx < self.samples.len() {
+            self.merge_and_insert(&self.samples[idx], &mut prev_r);
+            idx += 1;
+        }
+
+        self.buffer.clear();
+
+        std::mem::swap(&mut self.samples, &mut self.new_samples.borrow_mut());
+        self.new_samples.borrow_mut().clear();
+    }
+
+    fn merge_and_insert(&self, current: &Entry<T>, prev_r: &mut f64) {
+        let mut new_samples = self.new_samples.borrow_mut();
+        if new_samples.is_empty() {
+            new_samples.push(current.clone());
+            return;
+        }
+        let last_idx = new_samples.len() - 1;
+        let prev = &mut new_samples[last_idx];
+        if prev.g + current.g + current.delta as f64 <= self.invariant() {
+            prev.g += current.g;
+            prev.v = current.v;
+            prev.delta = current.delta;
+        } else {
+            *prev_r += prev.g;
+            new_samples.push(current.clone());
+        }
+    }
+
+    /// Retrieve a value within `epsilon` of the exact rank of `quantile`. Unlike
+    /// [`Stream::query`](struct.Stream.html#method.query), `quantile` does not need to have
+    /// been declared up front: any value in `[0, 1]` can be queried.
+    /// Defaults to `T::default()` as a convention if no value has been fed to this stream
+    /// using [`observe()`](#method.observe).
+    pub fn query(&mut self, quantile: f64) -> T {
+        self.flush_and_compress();
+
+        if self.samples.is_empty() {
+            return T::default();
+        }
+
+        let rank = (quantile * self.number_items as f64).ceil();
+        let t = rank + self.invariant() / 2.0;
+        let mut r = 0.0_f64;
+        for i in 1..self.samples.len() - 1 {
+            r += self.samples[i - 1].g;
+            let current = &self.samples[i];
+            if r + current.g + current.delta as f64 > t {
+                return self.samples[i - 1].v;
+            }
+        }
+        self.samples[self.samples.len() - 1].v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_any_quantile_at_runtime() {
+        let mut stream: UniformStream<f64> = UniformStream::new(0.05);
+
+        for i in 1..101 {
+            stream.observe(i as f64);
+        }
+
+        assert_in_error(stream.query(0.5), 50.0, 0.05, 100.0);
+        assert_in_error(stream.query(0.9), 90.0, 0.05, 100.0);
+        assert_in_error(stream.query(0.1), 10.0, 0.05, 100.0);
+    }
+
+    // Checks that `value` is within `epsilon * n` ranks of `expected`, the error bound
+    // `UniformStream` guarantees for a stream of `n` observations.
+    fn assert_in_error(value: f64, expected: f64, epsilon: f64, n: f64) {
+        let tolerance = epsilon * n;
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "expected {} to be within {} of {}",
+            value, tolerance, expected
+        );
+    }
+
+    #[test]
+    fn no_observation() {
+        let mut stream: UniformStream<f64> = UniformStream::new(0.05);
+        assert_eq!(0.0, stream.query(0.5));
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_if_epsilon_is_negative() {
+        let _stream: UniformStream<f64> = UniformStream::new(-0.05);
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_if_epsilon_is_greater_than_one() {
+        let _stream: UniformStream<f64> = UniformStream::new(1.5);
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2019 thibaultam (lathib2@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use crate::{Quantile, Stream};
+
+/// `WindowedStream` computes quantiles over a recent time horizon instead of over all of a
+/// stream's history, the way a Prometheus-style summary ages out observations using
+/// `max_age`/`age_buckets`.
+///
+/// Internally it keeps a ring of `age_buckets` [`Stream`](struct.Stream.html)s, each covering
+/// `max_age / age_buckets` of wall-clock time. `observe` always feeds the current head bucket;
+/// once a bucket has been alive for longer than its share of `max_age`, the ring rotates: the
+/// oldest bucket is recycled (cleared and made the new head), so observations older than
+/// `max_age` are dropped a bucket at a time rather than all at once.
+pub struct WindowedStream<T: Copy + PartialOrd + Default> {
+    targets: Vec<Quantile>,
+    buckets: Vec<Stream<T>>,
+    bucket_duration: Duration,
+    head: usize,
+    head_started_at: Instant,
+}
+
+impl<T: Copy + PartialOrd + Default> WindowedStream<T> {
+    /// Creates a new windowed stream that reports quantiles over the last `max_age`, split
+    /// into `age_buckets` buckets of `max_age / age_buckets` each.
+    ///
+    /// # panic
+    ///
+    /// Panics if `age_buckets` is `0`.
+    pub fn new(targets: Vec<Quantile>, max_age: Duration, age_buckets: usize) -> WindowedStream<T> {
+        assert!(age_buckets > 0, "age_buckets should be > 0");
+
+        let buckets = (0..age_buckets)
+            .map(|_| Stream::new(targets.clone()))
+            .collect();
+
+        WindowedStream {
+            targets,
+            buckets,
+            bucket_duration: max_age / age_buckets as u32,
+            head: 0,
+            head_started_at: Instant::now(),
+        }
+    }
+
+    /// Adds a new value to the current head bucket, rotating the ring first if the head
+    /// bucket has been alive longer than its share of `max_age`.
+    pub fn observe(&mut self, value: T) {
+        self.rotate_if_needed();
+        self.buckets[self.head].observe(value);
+    }
+
+    /// Retrieve the value that is within the defined error margin around `quantile`, computed
+    /// over the observations still within the window.
+    pub fn query(&mut self, quantile: f64) -> T {
+        self.rotate_if_needed();
+
+        let mut combined = Stream::new(self.targets.clone());
+        for bucket in self.buckets.iter_mut() {
+            combined.merge(bucket);
+        }
+        combined.query(quantile)
+    }
+
+    // Advances the head to the next bucket and clears it, so it can start accumulating fresh
+    // observations. The bucket being recycled is the one that is about to turn `max_age` old.
+    fn rotate(&mut self) {
+        self.head = (self.head + 1) % self.buckets.len();
+        self.buckets[self.head] = Stream::new(self.targets.clone());
+    }
+
+    // Rotates as many buckets as have fully elapsed since `head_started_at`, so an idle gap
+    // longer than a single `bucket_duration` (or even longer than the whole window) still ages
+    // out every bucket that should no longer be within `max_age`, instead of only the one.
+    fn rotate_if_needed(&mut self) {
+        let elapsed = self.head_started_at.elapsed();
+        if elapsed < self.bucket_duration {
+            return;
+        }
+
+        let elapsed_periods = elapsed.as_nanos() / self.bucket_duration.as_nanos();
+        let rotations = std::cmp::min(elapsed_periods as usize, self.buckets.len());
+        for _ in 0..rotations {
+            self.rotate();
+        }
+
+        // Advance by *every* elapsed period, not just `rotations * bucket_duration`: once
+        // `rotations` is capped at `buckets.len()`, every bucket has already been recycled, so
+        // there is nothing left to age out further and `head_started_at` must not lag behind
+        // real time, or the next call would rotate the whole ring again and wipe the bucket
+        // this call just cleared for fresh observations.
+        self.head_started_at = match std::convert::TryFrom::try_from(elapsed_periods)
+            .ok()
+            .and_then(|periods: u32| self.bucket_duration.checked_mul(periods))
+        {
+            Some(advance) => self.head_started_at + advance,
+            None => Instant::now(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_returns_a_value_within_the_window() {
+        let mut stream: WindowedStream<f64> =
+            WindowedStream::new(vec![Quantile::new(0.5, 0.05)], Duration::from_secs(60), 6);
+
+        for i in 1..101 {
+            stream.observe(i as f64);
+        }
+
+        let median = stream.query(0.5);
+        assert!(
+            (45.0..=55.0).contains(&median),
+            "expected a value close to the true median of 50.0, got {}",
+            median
+        );
+    }
+
+    #[test]
+    fn query_ages_out_stale_buckets_after_an_idle_gap() {
+        let mut stream: WindowedStream<f64> = WindowedStream::new(
+            vec![Quantile::new(0.9, 0.01)],
+            Duration::from_millis(300),
+            6,
+        );
+
+        stream.observe(1000.0);
+        std::thread::sleep(Duration::from_millis(800));
+        stream.observe(1.0);
+
+        let p90 = stream.query(0.9);
+        assert!(
+            p90 < 10.0,
+            "expected the idle gap to age out the 1000.0 observation, got {}",
+            p90
+        );
+    }
+
+    #[test]
+    fn observe_after_an_idle_gap_longer_than_the_window_is_not_dropped() {
+        // Regression test: `rotate_if_needed` used to cap the rotation count at `buckets.len()`
+        // without advancing `head_started_at` past the cap, so the very next call thought the
+        // head bucket was still stale and rotated the whole ring again, wiping out whatever had
+        // just been observed into it.
+        let mut stream: WindowedStream<f64> = WindowedStream::new(
+            vec![Quantile::new(0.5, 0.01)],
+            Duration::from_millis(300),
+            6,
+        );
+
+        std::thread::sleep(Duration::from_millis(800));
+        stream.observe(42.0);
+
+        assert_eq!(42.0, stream.query(0.5));
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_if_age_buckets_is_zero() {
+        let _stream: WindowedStream<f64> =
+            WindowedStream::new(vec![Quantile::new(0.5, 0.05)], Duration::from_secs(60), 0);
+    }
+}